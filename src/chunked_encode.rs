@@ -0,0 +1,121 @@
+//! Chunked-body framing shared by the client and server `Encoder`s.
+//!
+//! Both encoders need to turn a body of unknown length into
+//! `Transfer-Encoding: chunked` data one `poll_read` at a time, and a single
+//! call may only have room for part of a chunk's size line, part of the
+//! chunk data, or part of the trailing CRLF, so the state has to resume
+//! mid-chunk across calls. Kept in one place so a fix to the state machine
+//! only has to land once instead of being copied by hand into both files.
+
+use async_std::task::{Context, Poll};
+use futures_io::AsyncRead;
+use std::io;
+use std::pin::Pin;
+
+/// The size of the scratch buffer used to pull a chunk's worth of bytes out
+/// of the body before it's framed and handed back to the caller.
+pub(crate) const CHUNK_BUF_SIZE: usize = 8 * 1024;
+
+/// Where we are in writing out a chunked body.
+///
+/// A single `poll_read` call may only have room in `buf` for part of a
+/// chunk's size line, part of the chunk data, or part of the trailing
+/// CRLF, so we have to be able to resume mid-chunk across calls.
+#[derive(Debug)]
+pub(crate) enum ChunkedState {
+    /// About to pull the next chunk's worth of bytes out of the body.
+    Head,
+    /// Flushing a framed chunk (`size\r\nDATA\r\n`, or the terminating
+    /// `0\r\n\r\n`) out of `framed[cursor..]`. `last` marks the terminator.
+    Chunk {
+        framed: Vec<u8>,
+        cursor: usize,
+        last: bool,
+    },
+    /// The terminating chunk has been fully written.
+    Done,
+}
+
+impl ChunkedState {
+    /// Frame as much of `body` as fits into `buf[*bytes_read..]` as HTTP/1.1
+    /// chunks, resuming from `*state` if a previous call left a chunk
+    /// partially flushed.
+    ///
+    /// If the body returns `Pending` after some bytes have already been
+    /// framed and copied this call, returns `Ready` with those bytes kept
+    /// rather than dropping them; only returns `Pending` when nothing at all
+    /// was written. Sets `*body_done` once the terminating chunk has been
+    /// fully flushed.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn poll_chunked<R: AsyncRead + Unpin>(
+        state: &mut Option<ChunkedState>,
+        mut body: Pin<&mut R>,
+        cx: &mut Context<'_>,
+        scratch: &mut [u8],
+        buf: &mut [u8],
+        bytes_read: &mut usize,
+        body_bytes_read: &mut usize,
+        body_done: &mut bool,
+    ) -> Poll<io::Result<()>> {
+        let mut cur = state
+            .take()
+            .expect("poll_chunked called without chunked state");
+        while *bytes_read < buf.len() {
+            cur = match cur {
+                ChunkedState::Done => {
+                    *body_done = true;
+                    break;
+                }
+                ChunkedState::Chunk {
+                    framed,
+                    mut cursor,
+                    last,
+                } => {
+                    let len = std::cmp::min(framed.len() - cursor, buf.len() - *bytes_read);
+                    buf[*bytes_read..*bytes_read + len]
+                        .copy_from_slice(&framed[cursor..cursor + len]);
+                    cursor += len;
+                    *bytes_read += len;
+                    if cursor == framed.len() {
+                        if last {
+                            ChunkedState::Done
+                        } else {
+                            ChunkedState::Head
+                        }
+                    } else {
+                        ChunkedState::Chunk {
+                            framed,
+                            cursor,
+                            last,
+                        }
+                    }
+                }
+                ChunkedState::Head => {
+                    let n = match body.as_mut().poll_read(cx, scratch) {
+                        Poll::Ready(res) => res?,
+                        Poll::Pending => {
+                            *state = Some(ChunkedState::Head);
+                            return if *bytes_read > 0 {
+                                Poll::Ready(Ok(()))
+                            } else {
+                                Poll::Pending
+                            };
+                        }
+                    };
+                    *body_bytes_read += n;
+
+                    let mut framed = format!("{:X}\r\n", n).into_bytes();
+                    framed.extend_from_slice(&scratch[..n]);
+                    framed.extend_from_slice(b"\r\n");
+                    ChunkedState::Chunk {
+                        framed,
+                        cursor: 0,
+                        last: n == 0,
+                    }
+                }
+            };
+        }
+        *state = Some(cur);
+        Poll::Ready(Ok(()))
+    }
+}
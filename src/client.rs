@@ -14,9 +14,14 @@ use std::pin::Pin;
 use std::str::FromStr;
 
 use crate::chunked::ChunkedDecoder;
+use crate::chunked_encode::{ChunkedState, CHUNK_BUF_SIZE};
 use crate::date::fmt_http_date;
 use crate::MAX_HEADERS;
 
+/// Default cap on the size of a response head, to bound a slowloris-style
+/// peer who never finishes sending headers.
+const MAX_BUFFER_SIZE: usize = 131_072;
+
 /// An HTTP encoder.
 #[derive(Debug)]
 pub struct Encoder {
@@ -32,11 +37,28 @@ pub struct Encoder {
     body_done: bool,
     /// Keep track of how many bytes have been read from the body stream.
     body_bytes_read: usize,
+    /// Framing state for a chunked body; `None` when the body has a known
+    /// length and is copied straight through instead.
+    chunked: Option<ChunkedState>,
+    /// Scratch buffer a chunked body is read into before it's framed,
+    /// reused across chunks instead of reallocating one per chunk. Empty
+    /// when the body has a known length and isn't chunked.
+    chunk_scratch: Vec<u8>,
 }
 
 impl Encoder {
     /// Create a new instance.
     pub(crate) fn new(headers: Vec<u8>, request: Request) -> Self {
+        let chunked = if request.len().is_none() {
+            Some(ChunkedState::Head)
+        } else {
+            None
+        };
+        let chunk_scratch = if chunked.is_some() {
+            vec![0; CHUNK_BUF_SIZE]
+        } else {
+            Vec::new()
+        };
         Self {
             request,
             headers,
@@ -44,6 +66,8 @@ impl Encoder {
             headers_done: false,
             body_done: false,
             body_bytes_read: 0,
+            chunked,
+            chunk_scratch,
         }
     }
 }
@@ -107,10 +131,10 @@ pub async fn encode(req: Request) -> Result<Encoder, Error> {
         log::trace!("> {}", &val);
         buf.write_all(val.as_bytes()).await?;
     } else {
-        // write!(&mut buf, "Transfer-Encoding: chunked\r\n")?;
-        panic!("chunked encoding is not implemented yet");
+        let val = "transfer-encoding: chunked\r\n";
+        log::trace!("> {}", &val);
+        buf.write_all(val.as_bytes()).await?;
         // See: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Transfer-Encoding
-        //      https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Trailer
     }
 
     let date = fmt_http_date(std::time::SystemTime::now());
@@ -141,17 +165,48 @@ where
     let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut httparse_res = httparse::Response::new(&mut headers);
 
-    // Keep reading bytes from the stream until we hit the end of the stream.
+    // Keep pulling whatever's available off the stream until we hit the end
+    // delimiter or the stream ends. Reading via `fill_buf`/`consume`
+    // (instead of `read_until`, which doesn't return until it sees a `\n`)
+    // means the size check below runs after every chunk actually read, so
+    // a single line with no `\n` is capped too, not just a long sequence
+    // of short ones.
+    //
+    // `fill_buf` can hand back more than just the head -- the response body
+    // may already have arrived in the same read -- so only the bytes up to
+    // and including `\r\n\r\n` are pulled into `buf` and consumed; anything
+    // past the terminator is left sitting in `reader` for the body to read.
     loop {
-        let bytes_read = reader.read_until(b'\n', &mut buf).await?;
-        // No more bytes are yielded from the stream.
-        if bytes_read == 0 {
-            panic!("empty response");
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Err(Error::from_str(
+                ErrorKind::InvalidData,
+                "Connection closed before the response head finished",
+                StatusCode::BadRequest,
+            ));
+        }
+
+        let old_len = buf.len();
+        let search_from = old_len.saturating_sub(3);
+        buf.extend_from_slice(available);
+        let terminator = buf[search_from..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| search_from + i + 4);
+
+        let head_end = terminator.unwrap_or(buf.len());
+        reader.consume(head_end - old_len);
+        buf.truncate(head_end);
+
+        if buf.len() > MAX_BUFFER_SIZE {
+            return Err(Error::from_str(
+                ErrorKind::InvalidData,
+                "Response head exceeds the maximum buffer size",
+                StatusCode::BadRequest,
+            ));
         }
 
-        // We've hit the end delimiter of the stream.
-        let idx = buf.len() - 1;
-        if idx >= 3 && &buf[idx - 3..=idx] == b"\r\n\r\n" {
+        if terminator.is_some() {
             break;
         }
     }
@@ -265,14 +320,60 @@ impl Read for Encoder {
         }
 
         if !self.body_done {
-            let n = ready!(Pin::new(&mut self.request).poll_read(cx, &mut buf[bytes_read..]))?;
-            bytes_read += n;
-            self.body_bytes_read += n;
-            if bytes_read == 0 {
-                self.body_done = true;
+            if self.chunked.is_some() {
+                let Self {
+                    chunked,
+                    request,
+                    chunk_scratch,
+                    body_bytes_read,
+                    body_done,
+                    ..
+                } = &mut *self;
+                ready!(ChunkedState::poll_chunked(
+                    chunked,
+                    Pin::new(request),
+                    cx,
+                    chunk_scratch,
+                    buf,
+                    &mut bytes_read,
+                    body_bytes_read,
+                    body_done,
+                ))?;
+            } else {
+                let n = ready!(Pin::new(&mut self.request).poll_read(cx, &mut buf[bytes_read..]))?;
+                bytes_read += n;
+                self.body_bytes_read += n;
+                if bytes_read == 0 {
+                    self.body_done = true;
+                }
             }
         }
 
         Poll::Ready(Ok(bytes_read as usize))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{noop_waker, PendingAfterOneChunk};
+    use http_types::{Method, Url};
+
+    #[test]
+    fn chunked_encoder_does_not_drop_bytes_already_framed_on_pending() {
+        let mut req = Request::new(Method::Post, Url::parse("http://example.com").unwrap());
+        req.set_body(Body::from_reader(
+            BufReader::new(PendingAfterOneChunk::new()),
+            None,
+        ));
+        let mut encoder = Encoder::new(Vec::new(), req);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut out = vec![0u8; 64];
+
+        match Pin::new(&mut encoder).poll_read(&mut cx, &mut out) {
+            Poll::Ready(Ok(n)) => assert!(n > 0, "the first chunk must not be dropped"),
+            other => panic!("expected the already-framed chunk, got {:?}", other),
+        }
+    }
+}
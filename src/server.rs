@@ -1,14 +1,19 @@
 //! Process HTTP connections on the server.
 
-use async_std::io::{self, BufReader};
+use async_std::io::{self, BufReader, Read, Write};
 use async_std::prelude::*;
 use async_std::task::{Context, Poll};
 use futures_core::ready;
 use futures_io::AsyncRead;
-use http::{Request, Response, Version};
+use http::{header::CONNECTION, HeaderValue, Request, Response, Version};
 
+use std::cell::RefCell;
+use std::future::Future;
 use std::pin::Pin;
+use std::rc::Rc;
 
+use crate::chunked::ChunkedDecoder;
+use crate::chunked_encode::{ChunkedState, CHUNK_BUF_SIZE};
 use crate::{Body, Exception, MAX_HEADERS};
 
 /// A streaming HTTP encoder.
@@ -28,11 +33,28 @@ pub struct Encoder<R: AsyncRead> {
     body_done: bool,
     /// Keep track of how many bytes have been read from the body stream.
     body_bytes_read: usize,
+    /// Framing state for a chunked body; `None` when the body has a known
+    /// length and is copied straight through instead.
+    chunked: Option<ChunkedState>,
+    /// Scratch buffer a chunked body is read into before it's framed,
+    /// reused across chunks instead of reallocating one per chunk. Empty
+    /// when the body has a known length and isn't chunked.
+    chunk_scratch: Vec<u8>,
 }
 
 impl<R: AsyncRead> Encoder<R> {
     /// Create a new instance.
     pub(crate) fn new(headers: Vec<u8>, body: Body<R>) -> Self {
+        let chunked = if body.len().is_none() {
+            Some(ChunkedState::Head)
+        } else {
+            None
+        };
+        let chunk_scratch = if chunked.is_some() {
+            vec![0; CHUNK_BUF_SIZE]
+        } else {
+            Vec::new()
+        };
         Self {
             body,
             headers,
@@ -40,8 +62,17 @@ impl<R: AsyncRead> Encoder<R> {
             headers_done: false,
             body_done: false,
             body_bytes_read: 0,
+            chunked,
+            chunk_scratch,
         }
     }
+
+    /// Reclaim the header buffer for recycling into a [`BufferPool`] once
+    /// the encoder is done with it. This leaves the encoder with an empty
+    /// header buffer, so any headers not yet sent when it's called are lost.
+    pub(crate) fn take_headers_buf(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.headers)
+    }
 }
 
 impl<R: AsyncRead + Unpin> AsyncRead for Encoder<R> {
@@ -65,11 +96,32 @@ impl<R: AsyncRead + Unpin> AsyncRead for Encoder<R> {
         }
 
         if !self.body_done {
-            let n = ready!(Pin::new(&mut self.body).poll_read(cx, &mut buf[bytes_read..]))?;
-            bytes_read += n;
-            self.body_bytes_read += n;
-            if bytes_read == 0 {
-                self.body_done = true;
+            if self.chunked.is_some() {
+                let Self {
+                    chunked,
+                    body,
+                    chunk_scratch,
+                    body_bytes_read,
+                    body_done,
+                    ..
+                } = &mut *self;
+                ready!(ChunkedState::poll_chunked(
+                    chunked,
+                    Pin::new(body),
+                    cx,
+                    chunk_scratch,
+                    buf,
+                    &mut bytes_read,
+                    body_bytes_read,
+                    body_done,
+                ))?;
+            } else {
+                let n = ready!(Pin::new(&mut self.body).poll_read(cx, &mut buf[bytes_read..]))?;
+                bytes_read += n;
+                self.body_bytes_read += n;
+                if bytes_read == 0 {
+                    self.body_done = true;
+                }
             }
         }
 
@@ -79,11 +131,11 @@ impl<R: AsyncRead + Unpin> AsyncRead for Encoder<R> {
 
 /// Encode an HTTP request on the server.
 // TODO: return a reader in the response
-pub async fn encode<R>(res: Response<Body<R>>) -> io::Result<Encoder<R>>
+pub async fn encode<R>(res: Response<Body<R>>, pool: &BufferPool) -> io::Result<Encoder<R>>
 where
     R: AsyncRead,
 {
-    let mut buf: Vec<u8> = vec![];
+    let mut buf = pool.take();
 
     let reason = res.status().canonical_reason().unwrap();
     let status = res.status();
@@ -95,9 +147,7 @@ where
         write!(&mut buf, "Content-Length: {}\r\n", len).await?;
     } else {
         write!(&mut buf, "Transfer-Encoding: chunked\r\n").await?;
-        panic!("chunked encoding is not implemented yet");
         // See: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Transfer-Encoding
-        //      https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Trailer
     }
 
     for (header, value) in res.headers() {
@@ -114,27 +164,350 @@ where
     Ok(Encoder::new(buf, res.into_body()))
 }
 
+/// A `BufReader` shared between the head-parsing loop and the request body
+/// it hands off to a handler, so bytes buffered past one request (e.g. a
+/// pipelined next request) survive into the next call to [`decode`]. Must
+/// be driven from a single task (e.g. `spawn_local`), not a work-stealing
+/// pool, since `Rc` isn't `Send`.
+#[derive(Debug)]
+pub(crate) struct Shared<RW>(Rc<RefCell<BufReader<RW>>>);
+
+impl<RW> Shared<RW> {
+    pub(crate) fn new(raw: RW) -> Self {
+        Self(Rc::new(RefCell::new(BufReader::new(raw))))
+    }
+}
+
+impl<RW> Clone for Shared<RW> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Shared<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.borrow_mut()).poll_read(cx, buf)
+    }
+}
+
+/// The reader driving a decoded request body, selected by [`PayloadDecoder`].
+///
+/// A single concrete type is needed here because `decode` must return one
+/// `Body<_>` type no matter which framing the request used.
+#[derive(Debug)]
+pub(crate) enum PayloadReader<RW> {
+    /// Body is exactly `Content-Length` bytes of the underlying reader.
+    Length(io::Take<Shared<RW>>),
+    /// Body is `Transfer-Encoding: chunked`.
+    Chunked(ChunkedDecoder<Shared<RW>>),
+    /// No body was indicated.
+    None,
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for PayloadReader<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PayloadReader::Length(r) => Pin::new(r).poll_read(cx, buf),
+            PayloadReader::Chunked(r) => Pin::new(r).poll_read(cx, buf),
+            PayloadReader::None => Poll::Ready(Ok(0)),
+        }
+    }
+}
+
+/// Stashed as a request extension by [`decode`] so [`accept_upgrade`] can
+/// tell whether a request asked to upgrade without re-parsing its headers.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IsUpgrade(pub(crate) bool);
+
+/// An upgrade request: a `Connection: upgrade` header, or the `CONNECT` method.
+fn is_upgrade_request(method: Option<&str>, headers: &[httparse::Header<'_>]) -> bool {
+    if method == Some("CONNECT") {
+        return true;
+    }
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("connection"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .any(|t| t.eq_ignore_ascii_case("upgrade"))
+        })
+        .unwrap_or(false)
+}
+
+/// Stashed as a request extension by [`decode`]: true when the request
+/// sent `Expect: 100-continue` and is waiting on an interim response
+/// before it sends its body.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Expect100(pub(crate) bool);
+
+/// Detect an `Expect: 100-continue` header.
+fn expects_continue(headers: &[httparse::Header<'_>]) -> bool {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("expect"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+        .unwrap_or(false)
+}
+
+/// Where we are in writing the interim `100 Continue` line, if one is owed.
+#[derive(Debug)]
+enum Expect100State {
+    /// No `100 Continue` is owed; reads pass straight through.
+    Done,
+    /// Owed, but not sent yet -- sent on the first read of the body.
+    Pending,
+    /// Currently flushing `CONTINUE[cursor..]` to the stream.
+    Sending { cursor: usize },
+}
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Wraps a request body that may be owed an `Expect: 100-continue`. The
+/// interim `100 Continue` line is written to `stream` the first time the
+/// body is actually read, not before -- and at most once.
+#[derive(Debug)]
+pub(crate) struct ExpectContinue<RW, R> {
+    body: R,
+    stream: RW,
+    state: Expect100State,
+}
+
+impl<RW, R> ExpectContinue<RW, R> {
+    pub(crate) fn new(body: R, stream: RW, expects_continue: bool) -> Self {
+        Self {
+            body,
+            stream,
+            state: if expects_continue {
+                Expect100State::Pending
+            } else {
+                Expect100State::Done
+            },
+        }
+    }
+
+    /// True while a `100 Continue` is owed but hasn't been sent yet -- i.e.
+    /// nothing has tried to read the body. A conformant client in this state
+    /// is still waiting on the interim response and hasn't sent a body at
+    /// all, so it's not safe to read from `body` until this is no longer
+    /// `Pending`.
+    pub(crate) fn continue_pending(&self) -> bool {
+        matches!(self.state, Expect100State::Pending)
+    }
+}
+
+impl<RW: Write + Unpin, R: AsyncRead + Unpin> AsyncRead for ExpectContinue<RW, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match self.state {
+                Expect100State::Done => break,
+                Expect100State::Pending => self.state = Expect100State::Sending { cursor: 0 },
+                Expect100State::Sending { cursor } => {
+                    if cursor == CONTINUE.len() {
+                        self.state = Expect100State::Done;
+                        break;
+                    }
+                    let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &CONTINUE[cursor..]))?;
+                    self.state = Expect100State::Sending {
+                        cursor: cursor + n,
+                    };
+                }
+            }
+        }
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+/// Selects how to read a request body, based on the `Content-Length` and
+/// `Transfer-Encoding` headers.
+enum PayloadDecoder {
+    Length(u64),
+    Chunked,
+    None,
+}
+
+impl PayloadDecoder {
+    fn from_headers(headers: &[httparse::Header<'_>]) -> Result<Self, Exception> {
+        let mut content_length = None;
+        for header in headers {
+            if header.name.eq_ignore_ascii_case("content-length") {
+                if content_length.is_some() {
+                    // A request smuggling classic: two Content-Length
+                    // headers, possibly disagreeing, with this framing
+                    // picking one and a downstream proxy picking the other.
+                    return Err("Duplicate Content-Length header".into());
+                }
+                content_length = Some(header);
+            }
+        }
+
+        let mut transfer_encoding = None;
+        for header in headers {
+            if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                if transfer_encoding.is_some() {
+                    return Err("Duplicate Transfer-Encoding header".into());
+                }
+                transfer_encoding = Some(header);
+            }
+        }
+
+        if content_length.is_some() && transfer_encoding.is_some() {
+            return Err("Unexpected Content-Length header".into());
+        }
+
+        if let Some(header) = transfer_encoding {
+            let value = std::str::from_utf8(header.value)
+                .map_err(|_| Exception::from("Malformed Transfer-Encoding header"))?;
+            // Transfer-Encoding is a comma-separated list of codings; only
+            // the last one determines framing, so "gzip, chunked" is still
+            // a chunked body.
+            if let Some(last) = value.split(',').last() {
+                if last.trim().eq_ignore_ascii_case("chunked") {
+                    return Ok(PayloadDecoder::Chunked);
+                }
+            }
+        }
+
+        if let Some(header) = content_length {
+            let value = std::str::from_utf8(header.value)
+                .map_err(|_| Exception::from("Malformed Content-Length header"))?;
+            let len = value
+                .parse::<u64>()
+                .map_err(|_| Exception::from("Invalid Content-Length header"))?;
+            return Ok(PayloadDecoder::Length(len));
+        }
+
+        Ok(PayloadDecoder::None)
+    }
+}
+
+/// Initial capacity given to a buffer freshly allocated by a [`BufferPool`].
+/// Buffers grow past this only as needed, and keep whatever capacity
+/// they've grown to when recycled.
+const POOL_BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// A small per-connection pool of reusable head-read and header-encode
+/// buffers, so a pipelined keep-alive connection isn't allocating a fresh
+/// `Vec<u8>` for every request.
+#[derive(Debug, Clone)]
+pub(crate) struct BufferPool(Rc<RefCell<Vec<Vec<u8>>>>);
+
+impl BufferPool {
+    pub(crate) fn new() -> Self {
+        Self(Rc::new(RefCell::new(Vec::new())))
+    }
+
+    /// Take a buffer out of the pool, or allocate a fresh one sized to
+    /// `POOL_BUFFER_CAPACITY` if the pool is empty.
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.0
+            .borrow_mut()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(POOL_BUFFER_CAPACITY))
+    }
+
+    /// Clear a buffer and return it to the pool for the next request to
+    /// take, keeping whatever capacity it grew to.
+    pub(crate) fn recycle(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        self.0.borrow_mut().push(buf);
+    }
+}
+
+/// Default cap on the size of a request head, to bound a slowloris-style
+/// attacker who never finishes sending headers.
+pub const MAX_BUFFER_SIZE: usize = 131_072;
+
+/// Tunable limits applied while reading a request head.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Abort with an error once the accumulated head exceeds this many
+    /// bytes, whether or not the terminating blank line has been seen.
+    pub max_head_size: usize,
+    /// Give up on a head that hasn't finished arriving within this long.
+    /// `None` (the default) disables the deadline.
+    pub head_timeout: Option<std::time::Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_head_size: MAX_BUFFER_SIZE,
+            head_timeout: None,
+        }
+    }
+}
+
 /// Decode an HTTP request on the server.
-pub async fn decode<R>(reader: R) -> Result<Option<Request<Body<BufReader<R>>>>, Exception>
+///
+/// `shared` is cloned (cheaply: it's a reference-counted handle) into the
+/// returned request's body, so bytes the body reads stay visible to the
+/// next call to `decode` on the same connection.
+pub async fn decode<RW>(
+    shared: Shared<RW>,
+    config: &Config,
+    pool: &BufferPool,
+) -> Result<Option<Request<Body<PayloadReader<RW>>>>, Exception>
 where
-    R: AsyncRead + Unpin + Send,
+    RW: AsyncRead + Unpin,
 {
-    let mut reader = BufReader::new(reader);
-    let mut buf = Vec::new();
+    let mut buf = pool.take();
     let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut httparse_req = httparse::Request::new(&mut headers);
 
-    // Keep reading bytes from the stream until we hit the end of the stream.
+    // Keep pulling whatever's available off the stream until we hit the end
+    // delimiter or the stream ends. Reading via `fill_buf`/`consume`
+    // (instead of `read_until`, which doesn't return until it sees a `\n`)
+    // means the size check below runs after every chunk actually read, so
+    // a single line with no `\n` is capped too, not just a long sequence
+    // of short ones.
+    //
+    // `fill_buf` can hand back more than just the head -- body bytes, or a
+    // pipelined next request, may already have arrived in the same read --
+    // so only the bytes up to and including `\r\n\r\n` are pulled into `buf`
+    // and consumed; anything past the terminator is left sitting in the
+    // `BufReader` for the body reader or the next `decode` call to find.
     loop {
-        let bytes_read = reader.read_until(b'\n', &mut buf).await?;
-        // No more bytes are yielded from the stream.
-        if bytes_read == 0 {
+        let mut reader = shared.0.borrow_mut();
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            drop(reader);
+            pool.recycle(buf);
             return Ok(None);
         }
 
-        // We've hit the end delimiter of the stream.
-        let idx = buf.len() - 1;
-        if idx >= 3 && &buf[idx - 3..=idx] == b"\r\n\r\n" {
+        let old_len = buf.len();
+        let search_from = old_len.saturating_sub(3);
+        buf.extend_from_slice(available);
+        let terminator = buf[search_from..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| search_from + i + 4);
+
+        let head_end = terminator.unwrap_or(buf.len());
+        reader.consume(head_end - old_len);
+        buf.truncate(head_end);
+        drop(reader);
+
+        if buf.len() > config.max_head_size {
+            return Err("Request head exceeds the maximum buffer size".into());
+        }
+
+        if terminator.is_some() {
             break;
         }
     }
@@ -142,7 +515,6 @@ where
     // Convert our header buf into an httparse instance, and validate.
     let status = httparse_req.parse(&buf)?;
     if status.is_partial() {
-        dbg!(String::from_utf8(buf).unwrap());
         return Err("Malformed HTTP head".into());
     }
 
@@ -160,20 +532,678 @@ where
     if let Some(version) = httparse_req.version {
         req.version(match version {
             1 => Version::HTTP_11,
+            0 => Version::HTTP_10,
             _ => return Err("Unsupported HTTP version".into()),
         });
     }
+    req.extension(IsUpgrade(is_upgrade_request(
+        httparse_req.method,
+        httparse_req.headers,
+    )));
+    req.extension(Expect100(expects_continue(httparse_req.headers)));
 
-    // Process the body if `Content-Length` was passed.
-    let body = match httparse_req
-        .headers
-        .iter()
-        .find(|h| h.name == "Content-Length")
-    {
-        Some(_header) => Body::new(reader), // TODO: use the header value
-        None => Body::empty(),
+    // Select a body reader based on `Content-Length`/`Transfer-Encoding`.
+    let body = match PayloadDecoder::from_headers(httparse_req.headers)? {
+        PayloadDecoder::Length(len) => Body::new(PayloadReader::Length(shared.take(len))),
+        PayloadDecoder::Chunked => {
+            Body::new(PayloadReader::Chunked(ChunkedDecoder::new(shared, None)))
+        }
+        PayloadDecoder::None => Body::new(PayloadReader::None),
     };
 
+    // The head has been fully parsed into owned types above, so the raw
+    // buffer can go back to the pool for the next request on this
+    // connection.
+    pool.recycle(buf);
+
     // Return the request.
     Ok(Some(req.body(body)?))
-}
\ No newline at end of file
+}
+
+/// Decode a request head, aborting if it isn't complete within
+/// `config.head_timeout`.
+async fn decode_with_timeout<RW>(
+    shared: Shared<RW>,
+    config: &Config,
+    pool: &BufferPool,
+) -> Result<Option<Request<Body<PayloadReader<RW>>>>, Exception>
+where
+    RW: AsyncRead + Unpin,
+{
+    match config.head_timeout {
+        Some(duration) => async_std::future::timeout(duration, decode(shared, config, pool))
+            .await
+            .map_err(|_| Exception::from("Timed out waiting for the request head"))?,
+        None => decode(shared, config, pool).await,
+    }
+}
+
+/// A request body shared between the handler and the connection driver, so
+/// the driver can drain whatever the handler left unread -- same idea as
+/// [`Shared`], one layer up. Wraps the body in [`ExpectContinue`] rather than
+/// sharing the bare [`PayloadReader`] so both sides see the same
+/// `Expect: 100-continue` state: the driver needs to know whether the
+/// interim response was ever sent before it can safely drain.
+#[derive(Debug)]
+pub(crate) struct SharedBody<RW>(Rc<RefCell<ExpectContinue<RW, PayloadReader<RW>>>>);
+
+impl<RW> SharedBody<RW> {
+    fn new(reader: PayloadReader<RW>, stream: RW, expects_continue: bool) -> Self {
+        Self(Rc::new(RefCell::new(ExpectContinue::new(
+            reader,
+            stream,
+            expects_continue,
+        ))))
+    }
+}
+
+impl<RW> Clone for SharedBody<RW> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<RW: AsyncRead + Write + Unpin> AsyncRead for SharedBody<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.borrow_mut()).poll_read(cx, buf)
+    }
+}
+
+/// The body type a connection driver hands to its `endpoint`: a decoded
+/// request payload that sends an owed `Expect: 100-continue` the moment the
+/// handler actually starts reading it.
+pub(crate) type RequestBody<RW> = SharedBody<RW>;
+
+/// Wrap a decoded request's body in [`SharedBody`], and return a second
+/// handle onto the same body so the caller can drain it after `endpoint`
+/// returns, even if the handler never read it to the end.
+fn wrap_request_body<RW: Clone>(
+    req: Request<Body<PayloadReader<RW>>>,
+    stream: &RW,
+) -> (Request<Body<RequestBody<RW>>>, SharedBody<RW>) {
+    let expects_continue = req
+        .extensions()
+        .get::<Expect100>()
+        .map_or(false, |e| e.0);
+    let stream = stream.clone();
+    let (parts, body) = req.into_parts();
+    let shared_body = SharedBody::new(body.into_inner(), stream, expects_continue);
+    let drain_handle = shared_body.clone();
+    let body = Body::new(shared_body);
+    (Request::from_parts(parts, body), drain_handle)
+}
+
+/// Read a request body to completion and discard the bytes, so leftover
+/// bytes from a handler that didn't consume the whole body aren't parsed
+/// as the next request's head. Bounded by `MAX_BUFFER_SIZE`.
+///
+/// If the body is still owed an `Expect: 100-continue` that nothing ever
+/// sent -- i.e. the handler never read it -- this does nothing instead of
+/// reading: a conformant client hasn't sent the body yet and is waiting on
+/// the interim response the handler chose not to trigger, so reading here
+/// would stall the connection until the client's Expect-timeout (if it even
+/// has one).
+async fn drain_body<RW: AsyncRead + Write + Unpin>(
+    mut body: SharedBody<RW>,
+) -> Result<(), Exception> {
+    if body.0.borrow().continue_pending() {
+        return Ok(());
+    }
+    let mut scratch = [0u8; CHUNK_BUF_SIZE];
+    let mut drained = 0usize;
+    loop {
+        if drained > MAX_BUFFER_SIZE {
+            return Err("Request body exceeded the maximum drain size".into());
+        }
+        let n = body.read(&mut scratch).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        drained += n;
+    }
+}
+
+/// Whether the connection should stay open after this response: HTTP/1.1
+/// stays alive unless `Connection` says `close` or `upgrade`; HTTP/1.0 stays
+/// alive only if it explicitly says `keep-alive`.
+fn should_keep_alive<T>(req: &Request<T>) -> bool {
+    let tokens: Vec<&str> = req
+        .headers()
+        .get(CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+
+    match req.version() {
+        Version::HTTP_11 => !tokens
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case("close") || t.eq_ignore_ascii_case("upgrade")),
+        _ => tokens.iter().any(|t| t.eq_ignore_ascii_case("keep-alive")),
+    }
+}
+
+/// Drive a single connection to completion.
+///
+/// Repeatedly decodes a request off `raw_stream`, passes it to `endpoint`,
+/// and writes back the encoded response, reusing the connection for as
+/// long as keep-alive allows. Already-buffered bytes (a pipelined next
+/// request) are parsed without waiting on the socket again.
+pub async fn accept<RW, F, Fut, B>(
+    raw_stream: RW,
+    endpoint: F,
+    config: Config,
+) -> Result<(), Exception>
+where
+    RW: Read + Write + Clone + Unpin + 'static,
+    F: Fn(Request<Body<RequestBody<RW>>>) -> Fut,
+    Fut: Future<Output = Response<Body<B>>>,
+    B: AsyncRead + Unpin,
+{
+    let mut stream = raw_stream.clone();
+    let shared = Shared::new(raw_stream);
+    let pool = BufferPool::new();
+
+    // Each iteration decodes a request, waits for `endpoint` to finish with
+    // it, and flushes the response before the next `decode` runs -- so at
+    // most one request is ever read off the wire without its response
+    // having been flushed. There's no request count to bound here; the
+    // connection stays open for as long as `should_keep_alive` allows.
+    loop {
+        let req = match decode_with_timeout(shared.clone(), &config, &pool).await? {
+            Some(req) => req,
+            None => return Ok(()), // The peer closed the connection.
+        };
+
+        let keep_alive = should_keep_alive(&req);
+        let (req, drain) = wrap_request_body(req, &stream);
+        let mut res = endpoint(req).await;
+
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        res.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static(connection));
+
+        let mut encoder = encode(res, &pool).await?;
+        io::copy(&mut encoder, &mut stream).await?;
+        pool.recycle(encoder.take_headers_buf());
+
+        if !keep_alive {
+            return Ok(());
+        }
+
+        drain_body(drain).await?;
+    }
+}
+
+/// Write an upgrade response (`101 Switching Protocols`, or a successful
+/// `CONNECT` response) directly to `stream`. Unlike [`encode`], never adds
+/// a `Content-Length`/`Transfer-Encoding` -- RFC 7230 forbids both here.
+async fn write_upgrade_response<RW: Write + Unpin, B>(
+    res: &Response<Body<B>>,
+    stream: &mut RW,
+) -> io::Result<()>
+where
+    B: AsyncRead,
+{
+    let reason = res.status().canonical_reason().unwrap();
+    write!(stream, "HTTP/1.1 {} {}\r\n", res.status().as_str(), reason).await?;
+    for (header, value) in res.headers() {
+        write!(
+            stream,
+            "{}: {}\r\n",
+            header.as_str(),
+            value.to_str().unwrap()
+        )
+        .await?;
+    }
+    write!(stream, "\r\n").await?;
+    Ok(())
+}
+
+/// Returned by [`accept_upgrade`] when a request successfully upgraded the
+/// connection: the parsed request head, plus the raw duplex stream --
+/// including any bytes already buffered past the head -- for the caller to
+/// drive the upgraded protocol directly.
+pub struct Upgraded<RW> {
+    /// The request that asked for the upgrade.
+    pub head: Request<()>,
+    /// The raw stream, ready to be read from and written to as the
+    /// upgraded protocol (e.g. WebSocket framing, or a CONNECT tunnel).
+    pub stream: BufReader<RW>,
+}
+
+/// Outcome of [`accept_upgrade`] for a single request on the connection.
+pub enum Accepted<RW> {
+    /// The peer closed the connection before sending a request.
+    Disconnected,
+    /// An ordinary, non-upgrading request/response was handled and its
+    /// response written out. `keep_alive` reports whether the connection
+    /// may be reused for a further request, exactly as [`accept`] decides
+    /// between its own iterations -- the caller should stop reading from
+    /// `raw_stream` once it sees `false`.
+    Handled { keep_alive: bool },
+    /// The request upgraded the connection; the caller owns the raw stream.
+    Upgraded(Upgraded<RW>),
+}
+
+/// Accept a single request that may upgrade the connection (e.g. a
+/// WebSocket handshake, or an HTTP `CONNECT` tunnel). If `endpoint`'s
+/// response is a `101 Switching Protocols` (or, for `CONNECT`, any
+/// successful status), returns the raw stream instead of framing a body.
+/// Otherwise the ordinary response is written in full, with the same
+/// `Connection` header and keep-alive decision as [`accept`].
+pub async fn accept_upgrade<RW, F, Fut, B>(
+    raw_stream: RW,
+    endpoint: F,
+    config: Config,
+) -> Result<Accepted<RW>, Exception>
+where
+    RW: Read + Write + Clone + Unpin + 'static,
+    F: Fn(Request<Body<RequestBody<RW>>>) -> Fut,
+    Fut: Future<Output = Response<Body<B>>>,
+    B: AsyncRead + Unpin,
+{
+    let mut stream = raw_stream.clone();
+    let shared = Shared::new(raw_stream);
+    let pool = BufferPool::new();
+
+    let req = match decode_with_timeout(shared.clone(), &config, &pool).await? {
+        Some(req) => req,
+        None => return Ok(Accepted::Disconnected),
+    };
+
+    let is_upgrade = req
+        .extensions()
+        .get::<IsUpgrade>()
+        .map_or(false, |u| u.0);
+    let keep_alive = should_keep_alive(&req);
+    let (req, drain) = wrap_request_body(req, &stream);
+
+    let mut head = Request::builder();
+    head.method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version());
+    for (name, value) in req.headers() {
+        head.header(name, value);
+    }
+    let head = head.body(())?;
+    let is_connect = head.method() == http::Method::CONNECT;
+
+    let mut res = endpoint(req).await;
+    let upgraded = is_upgrade
+        && (res.status() == http::StatusCode::SWITCHING_PROTOCOLS
+            || (is_connect && res.status().is_success()));
+
+    if upgraded {
+        // Nothing is going to read the rest of the request body through
+        // this handle -- the caller takes over the raw stream instead --
+        // so drop it now. Otherwise it's still a live clone of `shared`
+        // (any framed body holds one internally), and the `Rc::try_unwrap`
+        // below would always fail.
+        drop(drain);
+
+        // A 101 (or a successful CONNECT) must not carry a Content-Length or
+        // Transfer-Encoding, so this bypasses `encode` entirely rather than
+        // have it add one.
+        write_upgrade_response(&res, &mut stream).await?;
+    } else {
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        res.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static(connection));
+
+        let mut encoder = encode(res, &pool).await?;
+        io::copy(&mut encoder, &mut stream).await?;
+        pool.recycle(encoder.take_headers_buf());
+
+        // Not actually upgrading, so this is an ordinary request/response --
+        // drain whatever the handler left unread, same as `accept` does
+        // between pipelined requests, in case this stream goes on to be
+        // reused.
+        drain_body(drain).await?;
+        return Ok(Accepted::Handled { keep_alive });
+    }
+
+    // `endpoint` has returned, so the request (and the only other clone of
+    // `shared` handed to its body) should have been dropped by now.
+    let reader = match Rc::try_unwrap(shared.0) {
+        Ok(cell) => cell.into_inner(),
+        Err(_) => return Err("request body outlived the upgrade handshake".into()),
+    };
+    Ok(Accepted::Upgraded(Upgraded {
+        head,
+        stream: reader,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{noop_waker, PendingAfterOneChunk};
+
+    #[test]
+    fn chunked_encoder_does_not_drop_bytes_already_framed_on_pending() {
+        let body = Body::new(PendingAfterOneChunk::new());
+        let mut encoder = Encoder::new(Vec::new(), body);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut out = vec![0u8; 64];
+
+        match Pin::new(&mut encoder).poll_read(&mut cx, &mut out) {
+            Poll::Ready(Ok(n)) => assert!(n > 0, "the first chunk must not be dropped"),
+            other => panic!("expected the already-framed chunk, got {:?}", other),
+        }
+    }
+
+    /// Synchronously yields `data`, then EOF. Writes are recorded into
+    /// `written` rather than discarded, so a test can assert on exactly
+    /// what was written to the stream.
+    struct FixedReader {
+        data: Vec<u8>,
+        pos: usize,
+        written: Vec<u8>,
+    }
+
+    impl AsyncRead for FixedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let remaining = self.data.len() - self.pos;
+            let n = std::cmp::min(remaining, buf.len());
+            let start = self.pos;
+            buf[..n].copy_from_slice(&self.data[start..start + n]);
+            self.pos += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl Write for FixedReader {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn drain_body_picks_up_where_the_handler_left_off() {
+        let shared = Shared::new(FixedReader {
+            data: vec![7u8; 50],
+            pos: 0,
+            written: Vec::new(),
+        });
+        let payload = PayloadReader::Length(shared.take(50));
+        // `SharedBody` requires its `RW` to be `Write` too, for the `stream` an
+        // owed `100 Continue` would be written to -- unused here since this test
+        // doesn't exercise `Expect: 100-continue`.
+        let mut handler_view = SharedBody::new(
+            payload,
+            FixedReader {
+                data: Vec::new(),
+                pos: 0,
+                written: Vec::new(),
+            },
+            false,
+        );
+        let drain_view = handler_view.clone();
+
+        async_std::task::block_on(async {
+            let mut small = [0u8; 10];
+            let n = handler_view.read(&mut small).await.unwrap();
+            assert_eq!(n, 10, "handler only reads part of the body");
+
+            drain_body(drain_view)
+                .await
+                .expect("drain should consume the other 40 bytes without erroring");
+        });
+    }
+
+    #[test]
+    fn decode_stops_the_head_at_the_blank_line_and_leaves_the_body_for_its_reader() {
+        // Headers, body, and some bytes of whatever comes next (a pipelined
+        // request, say) all arrive in a single read -- `fill_buf` hands back
+        // the lot, and `decode` must only pull the head out of it.
+        let raw = FixedReader {
+            data: b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nHELLOnext-request-bytes".to_vec(),
+            pos: 0,
+            written: Vec::new(),
+        };
+        let shared = Shared::new(raw);
+        let pool = BufferPool::new();
+        let config = Config::default();
+
+        async_std::task::block_on(async {
+            let req = decode(shared.clone(), &config, &pool)
+                .await
+                .expect("decode should succeed")
+                .expect("a full request was sent");
+            assert_eq!(req.headers().get("content-length").unwrap(), "5");
+
+            let mut buf = Vec::new();
+            req.into_body()
+                .read_to_end(&mut buf)
+                .await
+                .expect("reading the declared Content-Length must not error");
+            assert_eq!(
+                buf, b"HELLO",
+                "the body reader must see exactly the declared bytes, not the head or what follows"
+            );
+        });
+    }
+
+    #[test]
+    fn keep_alive_defaults_follow_the_http_version() {
+        let req_11 = Request::builder()
+            .version(Version::HTTP_11)
+            .body(())
+            .unwrap();
+        assert!(
+            should_keep_alive(&req_11),
+            "HTTP/1.1 defaults to keep-alive"
+        );
+
+        let req_10 = Request::builder()
+            .version(Version::HTTP_10)
+            .body(())
+            .unwrap();
+        assert!(!should_keep_alive(&req_10), "HTTP/1.0 defaults to close");
+    }
+
+    #[test]
+    fn keep_alive_honors_the_connection_header() {
+        let closing_11 = Request::builder()
+            .version(Version::HTTP_11)
+            .header(CONNECTION, "close")
+            .body(())
+            .unwrap();
+        assert!(
+            !should_keep_alive(&closing_11),
+            "HTTP/1.1 with Connection: close must not stay alive"
+        );
+
+        let upgrading_11 = Request::builder()
+            .version(Version::HTTP_11)
+            .header(CONNECTION, "upgrade")
+            .body(())
+            .unwrap();
+        assert!(
+            !should_keep_alive(&upgrading_11),
+            "HTTP/1.1 with Connection: upgrade must not stay alive"
+        );
+
+        let alive_10 = Request::builder()
+            .version(Version::HTTP_10)
+            .header(CONNECTION, "keep-alive")
+            .body(())
+            .unwrap();
+        assert!(
+            should_keep_alive(&alive_10),
+            "HTTP/1.0 with Connection: keep-alive must stay alive"
+        );
+
+        let mixed_case = Request::builder()
+            .version(Version::HTTP_11)
+            .header(CONNECTION, "Keep-Alive, Close")
+            .body(())
+            .unwrap();
+        assert!(
+            !should_keep_alive(&mixed_case),
+            "tokens are matched case-insensitively, even alongside other tokens"
+        );
+    }
+
+    #[test]
+    fn payload_decoder_rejects_duplicate_content_length_headers() {
+        let headers = [
+            httparse::Header {
+                name: "content-length",
+                value: b"5",
+            },
+            httparse::Header {
+                name: "content-length",
+                value: b"10",
+            },
+        ];
+        match PayloadDecoder::from_headers(&headers) {
+            Err(_) => {}
+            Ok(_) => panic!("a smuggled second Content-Length must not be silently ignored"),
+        }
+    }
+
+    #[test]
+    fn payload_decoder_rejects_duplicate_transfer_encoding_headers() {
+        let headers = [
+            httparse::Header {
+                name: "transfer-encoding",
+                value: b"chunked",
+            },
+            httparse::Header {
+                name: "transfer-encoding",
+                value: b"identity",
+            },
+        ];
+        match PayloadDecoder::from_headers(&headers) {
+            Err(_) => {}
+            Ok(_) => panic!("a smuggled second Transfer-Encoding must not be silently ignored"),
+        }
+    }
+
+    #[test]
+    fn payload_decoder_rejects_invalid_content_length() {
+        let headers = [httparse::Header {
+            name: "content-length",
+            value: b"not-a-number",
+        }];
+        match PayloadDecoder::from_headers(&headers) {
+            Err(_) => {}
+            Ok(_) => panic!("a non-numeric Content-Length must be rejected"),
+        }
+    }
+
+    #[test]
+    fn payload_decoder_rejects_overflowing_content_length() {
+        let headers = [httparse::Header {
+            name: "content-length",
+            // One past u64::MAX.
+            value: b"18446744073709551616",
+        }];
+        match PayloadDecoder::from_headers(&headers) {
+            Err(_) => {}
+            Ok(_) => panic!("a Content-Length that overflows u64 must be rejected"),
+        }
+    }
+
+    #[test]
+    fn expect_continue_writes_the_interim_response_on_first_read() {
+        let mut body = ExpectContinue::new(
+            FixedReader {
+                data: b"hello".to_vec(),
+                pos: 0,
+                written: Vec::new(),
+            },
+            FixedReader {
+                data: Vec::new(),
+                pos: 0,
+                written: Vec::new(),
+            },
+            true,
+        );
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut out = [0u8; 16];
+
+        match Pin::new(&mut body).poll_read(&mut cx, &mut out) {
+            Poll::Ready(Ok(n)) => assert_eq!(&out[..n], b"hello"),
+            other => panic!("expected the body to read through, got {:?}", other),
+        }
+        assert_eq!(
+            body.stream.written, CONTINUE,
+            "the literal 100 Continue line must be written to the stream on first read"
+        );
+    }
+
+    #[test]
+    fn expect_continue_sends_nothing_when_not_owed() {
+        let mut body = ExpectContinue::new(
+            FixedReader {
+                data: b"hello".to_vec(),
+                pos: 0,
+                written: Vec::new(),
+            },
+            FixedReader {
+                data: Vec::new(),
+                pos: 0,
+                written: Vec::new(),
+            },
+            false,
+        );
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut out = [0u8; 16];
+
+        match Pin::new(&mut body).poll_read(&mut cx, &mut out) {
+            Poll::Ready(Ok(n)) => assert_eq!(&out[..n], b"hello"),
+            other => panic!("expected the body to read through, got {:?}", other),
+        }
+        assert!(
+            body.stream.written.is_empty(),
+            "no 100 Continue is owed, so nothing should be written to the stream"
+        );
+    }
+
+    #[test]
+    fn buffer_pool_recycles_the_same_allocation() {
+        let pool = BufferPool::new();
+
+        let mut buf = pool.take();
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        pool.recycle(buf);
+
+        let recycled = pool.take();
+        assert_eq!(
+            recycled.as_ptr(),
+            ptr,
+            "recycling should hand back the same allocation, not a fresh one"
+        );
+        assert!(
+            recycled.is_empty(),
+            "a recycled buffer must be cleared before it's handed out again"
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Fixtures shared by `client`'s and `server`'s `poll_read` unit tests.
+#![cfg(test)]
+
+use async_std::io;
+use async_std::task::{Context, Poll};
+use futures_io::AsyncRead;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{RawWaker, RawWakerVTable, Waker};
+
+/// A waker that does nothing, for tests that only need to poll once.
+pub(crate) fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        raw()
+    }
+    fn noop(_: *const ()) {}
+    fn raw() -> RawWaker {
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    unsafe { Waker::from_raw(raw()) }
+}
+
+/// Yields one chunk of data, then `Pending` forever after.
+pub(crate) struct PendingAfterOneChunk(AtomicUsize);
+
+impl PendingAfterOneChunk {
+    pub(crate) fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+}
+
+impl AsyncRead for PendingAfterOneChunk {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.fetch_add(1, Ordering::SeqCst) {
+            0 => {
+                let data = b"hello";
+                buf[..data.len()].copy_from_slice(data);
+                Poll::Ready(Ok(data.len()))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}